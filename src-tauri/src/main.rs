@@ -4,19 +4,10 @@
 )]
 
 use anyhow::{Context as _, Result};
-use headless_chrome::{
-    protocol::network::{
-        events::ResponseReceivedEventParams, methods::GetResponseBodyReturnObject,
-    },
-    Browser, LaunchOptionsBuilder, Tab,
-};
-use std::{
-    future::Future,
-    pin::Pin,
-    sync::Arc,
-    task::Poll,
-    time::{Duration, Instant},
-};
+use regex::Regex;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::{future::Future, pin::Pin, sync::Arc, task::Poll, time::Duration};
 use tauri::Window;
 use tokio::{
     runtime::{Builder as TokioRuntimeBuilder, Runtime as TokioRuntime},
@@ -44,10 +35,12 @@ fn main() -> Result<()> {
         webview: Mutex::new(None),
     });
 
-    // failure::Error doesn't implement std::error::Error.
-    let (_browser, _tab) = YoutubeListener::new(Arc::clone(&context), todo!("put video id here"))
-        .start()
-        .expect("failed to initialize youtube listener");
+    let listener = YoutubeListener::new(Arc::clone(&context), todo!("put video id here"));
+    context.rt.spawn(async move {
+        if let Err(e) = listener.start().await {
+            tracing::error!("youtube listener stopped: {:#}", e);
+        }
+    });
 
     tauri::Builder::default()
         .on_page_load(move |window, _| {
@@ -75,8 +68,8 @@ impl Future for Empty {
 struct YoutubeListenerInner {
     ctx: Arc<Context>,
     video_id: String,
+    http: Client,
     comment_fetch_period: Mutex<CyclicArray<5>>,
-    last_comment_fetch: Mutex<Option<Instant>>,
 }
 
 struct YoutubeListener {
@@ -89,123 +82,191 @@ impl YoutubeListener {
             inner: Arc::new(YoutubeListenerInner {
                 ctx,
                 video_id,
+                http: Client::new(),
                 comment_fetch_period: Mutex::new(CyclicArray::new()),
-                last_comment_fetch: Mutex::new(None),
             }),
         }
     }
 
-    pub(crate) fn start(self) -> Result<(Browser, Arc<Tab>), failure::Error> {
-        let opt = LaunchOptionsBuilder::default()
-            .headless(false)
-            .build()
-            .unwrap();
-
-        let browser = Browser::new(opt)?;
-        let tab = browser.wait_for_initial_tab()?;
-
-        tab.enable_log()?;
+    // Keep the stream alive across a transient hiccup (dropped connection, a
+    // non-2xx response, an unexpected JSON shape) instead of letting one bad
+    // poll kill the listener for the rest of the broadcast.
+    const POLL_RETRY_BACKOFF: Duration = Duration::from_secs(5);
 
-        tab.navigate_to(&format!(
-            "https://www.youtube.com/live_chat?v={}",
-            self.inner.video_id
-        ))?;
+    pub(crate) async fn start(self) -> Result<()> {
+        let (api_key, mut continuation) = self.inner.fetch_initial_continuation().await?;
 
-        tab.enable_response_handling(Box::new(move |a, b| self.on_response(a, b)))?;
-
-        // why this element has this too short ID?
-        tab.wait_for_element_with_custom_timeout("#label", Duration::from_secs(10))?
-            .click()?;
-
-        std::thread::sleep(Duration::from_millis(500));
+        loop {
+            continuation = match self.inner.poll(&api_key, continuation.clone()).await {
+                Ok(next) => next,
+                Err(e) => {
+                    tracing::error!("live chat poll failed, retrying: {:#}", e);
+                    tokio::time::sleep(Self::POLL_RETRY_BACKOFF).await;
+                    continuation
+                }
+            };
+        }
+    }
+}
 
-        tab.wait_for_element("#menu > a:nth-child(2) > tp-yt-paper-item")?
-            .click()?;
+impl YoutubeListenerInner {
+    // YouTube accepts any recent-looking web client version; this one only
+    // needs to be new enough for the endpoint to respond.
+    const CLIENT_VERSION: &'static str = "2.20230101.00.00";
+
+    async fn fetch_initial_continuation(&self) -> Result<(String, String)> {
+        let html = self
+            .http
+            .get(format!(
+                "https://www.youtube.com/live_chat?v={}",
+                self.video_id
+            ))
+            .send()
+            .await
+            .context("failed to fetch live_chat page")?
+            .text()
+            .await
+            .context("failed to read live_chat page body")?;
+
+        let api_key = Self::extract_api_key(&html).context("failed to find INNERTUBE_API_KEY")?;
+        let continuation = Self::extract_initial_continuation(&html)
+            .context("failed to find initial continuation token")?;
+
+        Ok((api_key, continuation))
+    }
 
-        Ok((browser, tab))
+    fn extract_api_key(html: &str) -> Option<String> {
+        let re = Regex::new(r#""INNERTUBE_API_KEY":\s*"([^"]+)""#).unwrap();
+        Some(re.captures(html)?.get(1)?.as_str().to_owned())
     }
 
-    fn on_response(
-        &self,
-        param: ResponseReceivedEventParams,
-        fetch: &dyn Fn() -> Result<GetResponseBodyReturnObject, failure::Error>,
-    ) {
-        if !param
-            .response
-            .url
-            .starts_with("https://www.youtube.com/youtubei/v1/live_chat/get_live_chat")
-        {
-            return;
-        }
+    fn extract_initial_continuation(html: &str) -> Option<String> {
+        let raw = Self::extract_balanced_json(html, "var ytInitialData = ")?;
+        let data: Value = serde_json::from_str(raw).ok()?;
 
-        const POLL_COUNT: usize = 50;
-        const POLL_INTERVAL: Duration = Duration::from_millis(100);
-
-        let id = param.request_id;
-
-        // TODO: use tracing's span
-        // Wait for chromium to fetch response's body
-        // we don't have a way to be notified when chromium completed to fetch,
-        // so using polling instead.
-        'poll: for _ in 0..POLL_COUNT {
-            std::thread::sleep(POLL_INTERVAL);
-            match fetch() {
-                Ok(data) if data.body.trim().is_empty() => {
-                    tracing::warn!("id: {}: empty body. retrying", id);
-                    continue 'poll;
-                }
+        data.get("contents")?
+            .get("liveChatRenderer")?
+            .get("continuations")?
+            .as_array()?
+            .iter()
+            .find_map(Self::continuation_token)
+    }
 
-                Ok(data) => {
-                    // We should return from this function as soon as possible because
-                    // this `on_response` function is called by headless_chrome crate
-                    // *synchronously in event loop*. Blocking on this function too long time
-                    // causes dropping browser event or connection lost.
-                    // This is why using `spawn` instead of `block_on`.
-                    let inner = Arc::clone(&self.inner);
-                    self.inner
-                        .ctx
-                        .rt
-                        .spawn(async move { inner.on_comment(data.body).await });
-                    return;
+    /// Finds the first `{`...`}` object after `marker`, tracking brace depth and
+    /// string literals so embedded `}`-shaped substrings inside JSON strings
+    /// don't end the scan early (unlike a lazy regex would).
+    fn extract_balanced_json<'a>(html: &'a str, marker: &str) -> Option<&'a str> {
+        let after_marker = &html[html.find(marker)? + marker.len()..];
+        let object_start = after_marker.find('{')?;
+        let object = &after_marker[object_start..];
+
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for (i, c) in object.char_indices() {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
                 }
+                continue;
+            }
 
-                Err(e) => {
-                    tracing::error!("id: {}: failed to fetch: {}", id, e);
-                    return;
+            match c {
+                '"' => in_string = true,
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(&object[..=i]);
+                    }
                 }
+                _ => {}
             }
         }
 
-        tracing::warn!("couldn't fetch request's body");
+        None
     }
-}
 
-impl YoutubeListenerInner {
-    async fn on_comment(&self, json_str: String) {
-        let raw: serde_json::Value = serde_json::from_str(&json_str).unwrap();
-        let comments = match Self::parse_json(&raw) {
-            Some(t) => t,
-            None => return,
-        };
+    async fn poll(&self, api_key: &str, continuation: String) -> Result<String> {
+        let body = self
+            .http
+            .post(format!(
+                "https://www.youtube.com/youtubei/v1/live_chat/get_live_chat?key={}",
+                api_key
+            ))
+            .json(&json!({
+                "context": {
+                    "client": {
+                        "clientName": "WEB",
+                        "clientVersion": Self::CLIENT_VERSION,
+                    },
+                },
+                "continuation": continuation,
+            }))
+            .send()
+            .await
+            .context("failed to fetch live chat continuation")?
+            .text()
+            .await
+            .context("failed to read live chat continuation body")?;
+
+        let raw: Value =
+            serde_json::from_str(&body).context("failed to parse live chat response")?;
+
+        let (next_continuation, timeout) =
+            Self::parse_continuation(&raw).context("failed to find next continuation token")?;
+
+        match Self::parse_json(&raw) {
+            // `on_comment` already paces itself out over roughly `timeout` by spreading
+            // comment emission across it, so don't also sleep for `timeout` here - that
+            // would double the real poll cadence.
+            Some(comments) if !comments.is_empty() => self.on_comment(comments, timeout).await,
+            _ => tokio::time::sleep(timeout).await,
+        }
 
-        let normalize_duration = {
-            // used on first fetch where last_comment_fetch isn't set
-            const DEFAULT_FETCH_PERIOD_SECS: f64 = 5.2;
-
-            let now = Instant::now();
-            let elapsed_time_from_last_fetch = self
-                .last_comment_fetch
-                .lock()
-                .await
-                .replace(now)
-                .map(|x| now - x)
-                .unwrap_or(Duration::from_secs_f64(DEFAULT_FETCH_PERIOD_SECS));
+        Ok(next_continuation)
+    }
 
-            let mut comment_fetch_period = self.comment_fetch_period.lock().await;
-            comment_fetch_period.put(elapsed_time_from_last_fetch);
+    fn continuation_token(continuation: &Value) -> Option<String> {
+        continuation
+            .get("invalidationContinuationData")
+            .or_else(|| continuation.get("timedContinuationData"))
+            .or_else(|| continuation.get("reloadContinuationData"))?
+            .get("continuation")?
+            .as_str()
+            .map(str::to_owned)
+    }
 
-            let average = comment_fetch_period.average();
-            average
+    fn parse_continuation(raw: &Value) -> Option<(String, Duration)> {
+        let continuation = raw
+            .get("continuationContents")?
+            .get("liveChatContinuation")?
+            .get("continuations")?
+            .as_array()?
+            .first()?;
+
+        let token = Self::continuation_token(continuation)?;
+
+        let timeout_ms = continuation
+            .get("invalidationContinuationData")
+            .or_else(|| continuation.get("timedContinuationData"))
+            .and_then(|d| d.get("timeoutMs"))
+            .and_then(Value::as_u64)
+            .unwrap_or(5_000);
+
+        Some((token, Duration::from_millis(timeout_ms)))
+    }
+
+    async fn on_comment(&self, comments: Vec<SerializedComment<'_>>, timeout: Duration) {
+        let normalize_duration = {
+            let mut comment_fetch_period = self.comment_fetch_period.lock().await;
+            comment_fetch_period.put(timeout);
+            comment_fetch_period.average()
         };
 
         let comments_per_sec = comments.len() as f64 / normalize_duration.as_secs_f64();
@@ -346,6 +407,10 @@ impl<const N: usize> CyclicArray<N> {
     }
 
     fn average(&self) -> Duration {
+        // used when every populated slot holds a zero duration, e.g. a run of
+        // `timeoutMs: 0` continuations
+        const DEFAULT_FETCH_PERIOD: Duration = Duration::from_millis(5_200);
+
         let mut sum = Duration::ZERO;
         let mut count = 0;
         for d in self.value {
@@ -354,6 +419,11 @@ impl<const N: usize> CyclicArray<N> {
                 sum += d;
             }
         }
-        sum / count
+
+        if count == 0 {
+            DEFAULT_FETCH_PERIOD
+        } else {
+            sum / count
+        }
     }
 }